@@ -1,19 +1,23 @@
-use std::env;
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
 
 use anyhow::{Context, Result};
+use chrono::{Duration, Utc};
+use once_cell::sync::Lazy;
+use tokio::sync::RwLock;
 use k8s_openapi::api::core::v1 as corev1;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1 as metav1;
 use k8s_openapi::Metadata;
 use kube::{
-    api::{Api, PostParams},
     Client, Config,
     Service,
 };
 use kube_derive::CustomResource;
 use log::debug;
-use native_tls::Identity;
-use openssl::{pkcs12::Pkcs12, pkey::PKey, x509::X509};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use thiserror::Error;
@@ -22,6 +26,77 @@ use url::Url;
 const DEFAULT_PINNIPED_NAMESPACE: &str = "DEFAULT_PINNIPED_NAMESPACE";
 const DEFAULT_PINNIPED_AUTHENTICATOR_NAME: &str = "DEFAULT_PINNIPED_AUTHENTICATOR_NAME";
 const DEFAULT_PINNIPED_AUTHENTICATOR_TYPE: &str = "DEFAULT_PINNIPED_AUTHENTICATOR_TYPE";
+const DEFAULT_PINNIPED_IDP_NAME: &str = "DEFAULT_PINNIPED_IDP_NAME";
+const DEFAULT_PINNIPED_IDP_TYPE: &str = "DEFAULT_PINNIPED_IDP_TYPE";
+const DEFAULT_PINNIPED_CONCIERGE_API_GROUP: &str = "DEFAULT_PINNIPED_CONCIERGE_API_GROUP";
+const DEFAULT_PINNIPED_CONCIERGE_API_VERSION: &str = "DEFAULT_PINNIPED_CONCIERGE_API_VERSION";
+const DEFAULT_PINNIPED_CACHE_SKEW_SECONDS: &str = "DEFAULT_PINNIPED_CACHE_SKEW_SECONDS";
+
+// The concierge login API group/version the request is compiled against. These
+// match the `#[kube(...)]` attributes below and are used unless overridden via
+// the DEFAULT_PINNIPED_CONCIERGE_API_* env vars, so the same binary can talk to
+// concierge APIs across Pinniped generations without recompiling.
+const DEFAULT_CONCIERGE_API_GROUP: &str = "login.concierge.pinniped.dev";
+const DEFAULT_CONCIERGE_API_VERSION: &str = "v1alpha1";
+
+// The api group advertised by the IdP reference added to the request spec when
+// DEFAULT_PINNIPED_IDP_NAME/TYPE are set (federation-domain / OIDC-supervisor flows).
+const PINNIPED_IDP_API_GROUP: &str = "idp.supervisor.pinniped.dev";
+
+// Fall back to re-exchanging 10s before the concierge-reported expiry so a cached
+// identity is never handed out once it is about to (or has) expired.
+const DEFAULT_CACHE_SKEW_SECONDS: i64 = 10;
+
+/// CachedIdentity is an exchanged credential together with the expiry reported
+/// by the concierge, so it can be re-used until shortly before it expires.
+///
+/// The full token and target api server are retained so a lookup can confirm an
+/// exact match rather than trusting the hashed key alone: a `DefaultHasher`
+/// collision between two distinct tokens must never hand one caller another
+/// caller's credential.
+struct CachedIdentity {
+    authorization: String,
+    k8s_api_server_url: String,
+    credential: ExchangedCredential,
+    expiration_timestamp: metav1::Time,
+}
+
+impl CachedIdentity {
+    /// matches reports whether this entry was exchanged for exactly this token
+    /// and target api server, guarding against hash collisions.
+    fn matches(&self, authorization: &str, k8s_api_server_url: &str) -> bool {
+        self.authorization == authorization && self.k8s_api_server_url == k8s_api_server_url
+    }
+}
+
+/// IDENTITY_CACHE memoises exchanged identities keyed by a hash of the incoming
+/// authorization token and the target api server, mirroring how exec-credential
+/// clients reuse short-lived cluster credentials rather than re-exchanging on
+/// every call. The stored token/server are re-compared on lookup so a hash
+/// collision can never leak a credential. Expired entries are evicted lazily on
+/// lookup and also swept when a fresh credential is stored.
+static IDENTITY_CACHE: Lazy<RwLock<HashMap<u64, CachedIdentity>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// cache_key derives a lookup key from the token and target api server. It is
+/// only a bucket hint — callers must still confirm the match with
+/// `CachedIdentity::matches`, since distinct inputs can collide.
+fn cache_key(authorization: &str, k8s_api_server_url: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    authorization.hash(&mut hasher);
+    k8s_api_server_url.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// cache_skew returns how far ahead of the reported expiry a cached identity is
+/// considered stale, tunable via `DEFAULT_PINNIPED_CACHE_SKEW_SECONDS`.
+fn cache_skew() -> Duration {
+    let secs = env::var(DEFAULT_PINNIPED_CACHE_SKEW_SECONDS)
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_CACHE_SKEW_SECONDS);
+    Duration::seconds(secs)
+}
 
 #[derive(Error, Debug)]
 pub enum PinnipedError {
@@ -29,15 +104,44 @@ pub enum PinnipedError {
     UnsuccessfulAuthentication(String),
 }
 
-/// exchange_token_for_identity accepts an authorization header and returns a client cert authentication Identity in exchange.
+/// Identity is a rustls client TLS configuration carrying the exchanged client
+/// certificate and private key together with the cluster CA root store.
+///
+/// It replaces the former `native_tls::Identity`, keeping the credential
+/// exchange within pure-Rust crates so the binary no longer needs a system
+/// OpenSSL at build or run time (which is painful in distroless/scratch images).
+pub type Identity = ClientConfig;
+
+/// ExchangedCredential is the credential returned by a successful token exchange.
+///
+/// Pinniped's concierge can issue either a TLS client certificate/key pair or a
+/// bearer token (common with impersonation-proxy strategies), so callers attach
+/// either a client TLS identity or an `Authorization: Bearer` header to
+/// downstream requests.
+#[derive(Clone)]
+pub enum ExchangedCredential {
+    ClientCert(Identity),
+    BearerToken(String),
+}
+
+/// exchange_token_for_identity accepts an authorization header and returns the client cert or bearer credential issued in exchange.
 ///
 /// The token is exchanged with pinniped concierge API running on the identified kubernetes api server.
-pub async fn exchange_token_for_identity(authorization: &str, k8s_api_server_url: &str, k8s_api_ca_cert_data: &[u8]) -> Result<Identity> {
+pub async fn exchange_token_for_identity(authorization: &str, k8s_api_server_url: &str, k8s_api_ca_cert_data: &[u8]) -> Result<ExchangedCredential> {
+    let key = cache_key(authorization, k8s_api_server_url);
+    let skew = cache_skew();
+
+    // Re-use a previously exchanged identity while it remains comfortably within
+    // its validity window, avoiding a concierge round-trip on every request.
+    if let Some(credential) = cache_lookup(key, authorization, k8s_api_server_url, skew).await {
+        return Ok(credential);
+    }
+
     let credential_request = call_pinniped_exchange(authorization, k8s_api_server_url, k8s_api_ca_cert_data).await.context("Failed to exchange credentials")?;
-    match credential_request.status {
+    let (credential, expiration_timestamp) = match credential_request.status {
         Some(s) => {
             match s.credential {
-                Some(c) => return identity_for_exchange(&c),
+                Some(c) => (identity_for_exchange(&c, k8s_api_ca_cert_data)?, c.expiration_timestamp),
                 None => match s.message {
                     // A returned status without a credential is unsuccessful authentication so
                     // add context to identify this.
@@ -47,27 +151,116 @@ pub async fn exchange_token_for_identity(authorization: &str, k8s_api_server_url
             }
         },
         None => return Err(anyhow::anyhow!("pinniped credential request did not include status: {:#?}", credential_request))
+    };
+
+    // Cache the fresh identity and lazily evict any entries that have expired.
+    cache_store(key, authorization, k8s_api_server_url, credential.clone(), expiration_timestamp).await;
+
+    Ok(credential)
+}
+
+/// cache_lookup returns a cached credential for this exact token and target api
+/// server, but only while it stays more than `skew` ahead of its reported expiry.
+///
+/// A valid hit is served under a read lock. On a miss or a stale entry an expired
+/// entry for this key is evicted lazily, so a credential exchanged once and never
+/// refreshed does not linger past its expiry until some other token is stored.
+async fn cache_lookup(key: u64, authorization: &str, k8s_api_server_url: &str, skew: Duration) -> Option<ExchangedCredential> {
+    {
+        let cache = IDENTITY_CACHE.read().await;
+        if let Some(entry) = cache.get(&key) {
+            if entry.matches(authorization, k8s_api_server_url)
+                && entry.expiration_timestamp.0 - skew > Utc::now()
+            {
+                return Some(entry.credential.clone());
+            }
+        }
+    }
+
+    let mut cache = IDENTITY_CACHE.write().await;
+    if let Some(entry) = cache.get(&key) {
+        if entry.expiration_timestamp.0 <= Utc::now() {
+            cache.remove(&key);
+        }
     }
+    None
 }
 
-/// identity_for_exchange parses the JSON output of the credential exchange and returns the Identity.
+/// cache_store records a freshly exchanged credential and lazily evicts any
+/// entries that have already expired.
+async fn cache_store(key: u64, authorization: &str, k8s_api_server_url: &str, credential: ExchangedCredential, expiration_timestamp: metav1::Time) {
+    let now = Utc::now();
+    let mut cache = IDENTITY_CACHE.write().await;
+    cache.retain(|_, entry| entry.expiration_timestamp.0 > now);
+    cache.insert(key, CachedIdentity {
+        authorization: authorization.to_string(),
+        k8s_api_server_url: k8s_api_server_url.to_string(),
+        credential,
+        expiration_timestamp,
+    });
+}
+
+/// identity_for_exchange converts the exchanged cluster credential into an `ExchangedCredential`.
 ///
-/// Note: to create an identity, need to go via a pkcs12 currently.
-/// https://github.com/sfackler/rust-native-tls/issues/27#issuecomment-324262673
-fn identity_for_exchange(cred: &ClusterCredential) -> Result<Identity> {
-    let pkey = PKey::private_key_from_pem(cred.client_key_data.as_bytes())
-        .context("error creating private key from pem")?;
-    let x509 = X509::from_pem(cred.client_certificate_data.as_bytes())
+/// If the concierge issued a bearer token it is returned directly; otherwise the
+/// client certificate and private key are parsed from their PEM blocks (PKCS#8,
+/// RSA and EC key encodings are all accepted) and combined with a root store
+/// seeded from the supplied cluster CA to produce a `ClientConfig` that presents
+/// the exchanged identity to the target api server.
+fn identity_for_exchange(cred: &ClusterCredential, k8s_api_ca_cert_data: &[u8]) -> Result<ExchangedCredential> {
+    if let Some(token) = &cred.token {
+        if !token.is_empty() {
+            return Ok(ExchangedCredential::BearerToken(token.clone()));
+        }
+    }
+
+    let certs = load_certs(cred.client_certificate_data.as_bytes())
         .context("error creating x509 from pem")?;
+    let key = load_private_key(cred.client_key_data.as_bytes())
+        .context("error creating private key from pem")?;
+
+    let mut root_store = RootCertStore::empty();
+    for ca in load_certs(k8s_api_ca_cert_data).context("error creating x509 from pem")? {
+        root_store.add(&ca).context("error adding cluster CA to root store")?;
+    }
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_single_cert(certs, key)
+        .context("error building rustls client config from exchanged identity")?;
+    Ok(ExchangedCredential::ClientCert(config))
+}
 
-    let pkcs_cert = Pkcs12::builder()
-        .build("", "friendly-name", &pkey, &x509)
-        .context("Error building Pkcs12 from private key and x509")?;
-    let identity = Identity::from_pkcs12(
-        &pkcs_cert.to_der().context("error creating der from pkcs12")?,
-        "",
-    ).context("error creating identity from der-formatted pkcs12")?;
-    Ok(identity)
+/// load_certs parses every PEM-encoded certificate in the supplied bytes.
+fn load_certs(pem: &[u8]) -> Result<Vec<Certificate>> {
+    let mut reader = Cursor::new(pem);
+    let certs = rustls_pemfile::certs(&mut reader).context("error reading PEM certificates")?;
+    if certs.is_empty() {
+        anyhow::bail!(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "no PEM certificate found",
+        ));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// load_private_key parses the first PEM-encoded private key, accepting PKCS#8, RSA (PKCS#1) and SEC1 EC encodings.
+fn load_private_key(pem: &[u8]) -> Result<PrivateKey> {
+    use rustls_pemfile::Item;
+    let mut reader = Cursor::new(pem);
+    loop {
+        match rustls_pemfile::read_one(&mut reader).context("error reading PEM private key")? {
+            Some(Item::PKCS8Key(key)) | Some(Item::RSAKey(key)) | Some(Item::ECKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => continue,
+            None => anyhow::bail!(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "no PEM private key found",
+            )),
+        }
+    }
 }
 
 /// TokenCredentialRequestSpec
@@ -87,6 +280,11 @@ pub struct TokenCredentialRequestSpec {
 
     // Reference to an authenticator which can verify this credential request.
     authenticator: corev1::TypedLocalObjectReference,
+
+    // Optional reference to an identity provider, used by newer Pinniped releases
+    // for federation-domain / OIDC-supervisor flows. Older concierge APIs ignore it.
+    #[serde(rename = "identityProvider", skip_serializing_if = "Option::is_none")]
+    identity_provider: Option<corev1::TypedLocalObjectReference>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -116,22 +314,204 @@ pub struct ClusterCredential {
     client_key_data: String,
 }
 
+/// kubeconfig_for_identity renders a ready-to-use kubeconfig YAML for a Pinniped-protected cluster.
+///
+/// The target api server and (base64) CA are wired into a cluster/context/user
+/// triple. When the freshly exchanged client certificate and key are supplied
+/// they are embedded inline; otherwise a standard exec-credential plugin block
+/// is emitted so `kubectl` re-invokes the token exchange on demand, using the
+/// namespace/authenticator/IdP parameters read from the environment.
+pub fn kubeconfig_for_identity(
+    k8s_api_server_url: &str,
+    k8s_api_ca_cert_data: &[u8],
+    client_certificate_data: Option<&str>,
+    client_key_data: Option<&str>,
+) -> Result<String> {
+    let namespace = env::var(DEFAULT_PINNIPED_NAMESPACE).ok();
+    let ca_bundle_data = base64::encode(k8s_api_ca_cert_data);
+
+    let user = match (client_certificate_data, client_key_data) {
+        (Some(cert), Some(key)) => KubeconfigAuthInfo {
+            client_certificate_data: Some(base64::encode(cert)),
+            client_key_data: Some(base64::encode(key)),
+            exec: None,
+        },
+        _ => KubeconfigAuthInfo {
+            client_certificate_data: None,
+            client_key_data: None,
+            exec: Some(exec_credential_plugin(k8s_api_server_url, &ca_bundle_data)?),
+        },
+    };
+
+    let kubeconfig = Kubeconfig {
+        api_version: "v1".into(),
+        kind: "Config".into(),
+        clusters: vec![KubeconfigNamedCluster {
+            name: CLUSTER_NAME.into(),
+            cluster: KubeconfigCluster {
+                server: k8s_api_server_url.into(),
+                certificate_authority_data: Some(ca_bundle_data.clone()),
+            },
+        }],
+        contexts: vec![KubeconfigNamedContext {
+            name: CONTEXT_NAME.into(),
+            context: KubeconfigContext {
+                cluster: CLUSTER_NAME.into(),
+                user: USER_NAME.into(),
+                namespace,
+            },
+        }],
+        users: vec![KubeconfigNamedAuthInfo {
+            name: USER_NAME.into(),
+            user,
+        }],
+        current_context: CONTEXT_NAME.into(),
+    };
+
+    serde_yaml::to_string(&kubeconfig).context("error serializing kubeconfig")
+}
+
+/// The env var the generated exec block reads the user's bearer token from; the
+/// `pinniped` CLI picks it up via `--token-env`. Callers set it before `kubectl`
+/// invokes the plugin (mirroring how static-token login is normally driven).
+const PINNIPED_TOKEN_ENV: &str = "PINNIPED_TOKEN";
+
+/// exec_credential_plugin builds the exec block that re-invokes `pinniped login
+/// static`/`pinniped login oidc` to perform the token exchange on demand.
+///
+/// The concierge endpoint, CA bundle and authenticator are passed as the CLI's
+/// own flags (the tool does not read the proxy's internal `DEFAULT_PINNIPED_*`
+/// env vars). When an upstream identity provider is configured the federated
+/// `login oidc` subcommand is emitted — its `--upstream-identity-provider-*`
+/// flags do not exist on `login static` — otherwise `login static` sources the
+/// user's token from `PINNIPED_TOKEN` via `--token-env`, so the rendered
+/// kubeconfig actually authenticates in either case.
+fn exec_credential_plugin(k8s_api_server_url: &str, ca_bundle_data: &str) -> Result<KubeconfigExecConfig> {
+    let authenticator_name = env::var(DEFAULT_PINNIPED_AUTHENTICATOR_NAME).with_context(|| format!("error retrieving {}", DEFAULT_PINNIPED_AUTHENTICATOR_NAME))?;
+    let authenticator_type = env::var(DEFAULT_PINNIPED_AUTHENTICATOR_TYPE).with_context(|| format!("error retrieving {}", DEFAULT_PINNIPED_AUTHENTICATOR_TYPE))?;
+    let idp_name = env::var(DEFAULT_PINNIPED_IDP_NAME).ok().filter(|v| !v.is_empty());
+    let idp_type = env::var(DEFAULT_PINNIPED_IDP_TYPE).ok().filter(|v| !v.is_empty());
+
+    // Flags shared by both login subcommands.
+    let concierge = vec![
+        "--enable-concierge".to_string(),
+        format!("--concierge-endpoint={}", k8s_api_server_url),
+        format!("--concierge-ca-bundle-data={}", ca_bundle_data),
+        format!("--concierge-authenticator-name={}", authenticator_name),
+        format!("--concierge-authenticator-type={}", authenticator_type),
+    ];
+
+    let mut args = vec!["login".to_string()];
+    match (idp_name, idp_type) {
+        // Federation-domain / OIDC-supervisor flow: only `login oidc` understands
+        // the upstream identity provider flags.
+        (Some(idp_name), Some(idp_type)) => {
+            args.push("oidc".into());
+            args.extend(concierge);
+            args.push(format!("--upstream-identity-provider-name={}", idp_name));
+            args.push(format!("--upstream-identity-provider-type={}", idp_type));
+        }
+        // Static-token flow against a concierge authenticator.
+        _ => {
+            args.push("static".into());
+            args.extend(concierge);
+            args.push(format!("--token-env={}", PINNIPED_TOKEN_ENV));
+        }
+    }
+
+    Ok(KubeconfigExecConfig {
+        api_version: "client.authentication.k8s.io/v1beta1".into(),
+        command: "pinniped".into(),
+        args: Some(args),
+    })
+}
+
+// Stable names wiring the single cluster/context/user triple in the rendered kubeconfig.
+const CLUSTER_NAME: &str = "pinniped";
+const CONTEXT_NAME: &str = "pinniped";
+const USER_NAME: &str = "pinniped-user";
+
+/// Kubeconfig and the structs below mirror the `clientcmd` kubeconfig shape
+/// (clusters/contexts/users arrays) so they serialize to the YAML `kubectl`
+/// expects. Only the fields pinniped-proxy needs to populate are modelled.
+#[derive(Serialize)]
+struct Kubeconfig {
+    #[serde(rename = "apiVersion")]
+    api_version: String,
+    kind: String,
+    clusters: Vec<KubeconfigNamedCluster>,
+    contexts: Vec<KubeconfigNamedContext>,
+    users: Vec<KubeconfigNamedAuthInfo>,
+    #[serde(rename = "current-context")]
+    current_context: String,
+}
+
+#[derive(Serialize)]
+struct KubeconfigNamedCluster {
+    name: String,
+    cluster: KubeconfigCluster,
+}
+
+#[derive(Serialize)]
+struct KubeconfigCluster {
+    server: String,
+    #[serde(rename = "certificate-authority-data", skip_serializing_if = "Option::is_none")]
+    certificate_authority_data: Option<String>,
+}
+
+#[derive(Serialize)]
+struct KubeconfigNamedContext {
+    name: String,
+    context: KubeconfigContext,
+}
+
+#[derive(Serialize)]
+struct KubeconfigContext {
+    cluster: String,
+    user: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+}
+
+#[derive(Serialize)]
+struct KubeconfigNamedAuthInfo {
+    name: String,
+    user: KubeconfigAuthInfo,
+}
+
+#[derive(Serialize)]
+struct KubeconfigAuthInfo {
+    #[serde(rename = "client-certificate-data", skip_serializing_if = "Option::is_none")]
+    client_certificate_data: Option<String>,
+    #[serde(rename = "client-key-data", skip_serializing_if = "Option::is_none")]
+    client_key_data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exec: Option<KubeconfigExecConfig>,
+}
+
+#[derive(Serialize)]
+struct KubeconfigExecConfig {
+    #[serde(rename = "apiVersion")]
+    api_version: String,
+    command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<Vec<String>>,
+}
+
 /// call_pinniped_exchange returns the resulting TokenCredentialRequest with Status after requesting a token credential exchange.
 async fn call_pinniped_exchange(authorization: &str, k8s_api_server_url: &str, k8s_api_ca_cert_data: &[u8]) -> Result<TokenCredentialRequest> {
     let pinniped_namespace = env::var(DEFAULT_PINNIPED_NAMESPACE)?;
 
     let mut config = Config::new(Url::parse(k8s_api_server_url).context("Failed parsing url for exchange")?);
     config.default_ns = pinniped_namespace.clone();
-    let x509 = X509::from_pem(k8s_api_ca_cert_data).context("error creating x509 from pem")?;
-    let der = x509.to_der().context("error creating der from x509")?;
-    config.root_cert = Some(vec!(der));
+    let ca_certs = load_certs(k8s_api_ca_cert_data).context("error creating x509 from pem")?;
+    config.root_cert = Some(ca_certs.iter().map(|c| c.0.clone()).collect());
     let client = Client::new(Service::try_from(config)?);
 
     let auth_token = match authorization.to_string().strip_prefix("Bearer ") {
         Some(a) => a.to_string(),
         None => authorization.to_string(),
     };
-    let token_creds: Api<TokenCredentialRequest> = Api::namespaced(client.clone(), &pinniped_namespace);
     let mut cred_request = TokenCredentialRequest::new("", TokenCredentialRequestSpec {
         token: Some(auth_token),
         authenticator: corev1::TypedLocalObjectReference {
@@ -139,20 +519,49 @@ async fn call_pinniped_exchange(authorization: &str, k8s_api_server_url: &str, k
             kind: env::var(DEFAULT_PINNIPED_AUTHENTICATOR_TYPE).with_context(|| format!("error retrieving {}", DEFAULT_PINNIPED_AUTHENTICATOR_TYPE))?,
             api_group: Some("authentication.concierge.pinniped.dev".into()),
         },
+        identity_provider: identity_provider_from_env(),
     });
     // The pinniped authenticator cache requires the namespace of the request to be included
     // explicitly, even if the client is limited to a specific namespace.
-    cred_request.metadata_mut().namespace = Some(pinniped_namespace);
+    cred_request.metadata_mut().namespace = Some(pinniped_namespace.clone());
 
-    debug!("{}", serde_json::to_string(&cred_request).unwrap());
-    match token_creds.create(&PostParams::default(), &cred_request).await {
+    // The concierge login group/version is selectable at runtime so the same
+    // binary can talk to concierge APIs across Pinniped generations; the request
+    // shape is otherwise identical, so we route and stamp the body accordingly.
+    let api_group = env::var(DEFAULT_PINNIPED_CONCIERGE_API_GROUP).unwrap_or_else(|_| DEFAULT_CONCIERGE_API_GROUP.to_string());
+    let api_version = env::var(DEFAULT_PINNIPED_CONCIERGE_API_VERSION).unwrap_or_else(|_| DEFAULT_CONCIERGE_API_VERSION.to_string());
+    let path = format!("/apis/{}/{}/namespaces/{}/tokencredentialrequests", api_group, api_version, pinniped_namespace);
+
+    let mut body = serde_json::to_value(&cred_request).context("error serializing token exchange request")?;
+    body["apiVersion"] = serde_json::Value::String(format!("{}/{}", api_group, api_version));
+    let body = serde_json::to_vec(&body).context("error serializing token exchange request")?;
+
+    debug!("{}", String::from_utf8_lossy(&body));
+    let request = http::Request::post(&path)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body.clone())
+        .context("error building token exchange request")?;
+    match client.request::<TokenCredentialRequest>(request).await {
         Ok(o) => Ok(o),
         Err(e) => {
-            Err(anyhow::anyhow!("err creating token exchange: {:#?}\n{}", serde_json::to_string(&cred_request).unwrap(), e))
+            Err(anyhow::anyhow!("err creating token exchange: {:#?}\n{}", String::from_utf8_lossy(&body), e))
         },
     }
 }
 
+/// identity_provider_from_env builds the optional identity provider reference
+/// from `DEFAULT_PINNIPED_IDP_NAME`/`DEFAULT_PINNIPED_IDP_TYPE`, returning `None`
+/// (i.e. an authenticator-only request) unless both are set to a non-empty value.
+fn identity_provider_from_env() -> Option<corev1::TypedLocalObjectReference> {
+    let name = env::var(DEFAULT_PINNIPED_IDP_NAME).ok().filter(|v| !v.is_empty())?;
+    let kind = env::var(DEFAULT_PINNIPED_IDP_TYPE).ok().filter(|v| !v.is_empty())?;
+    Some(corev1::TypedLocalObjectReference {
+        name,
+        kind,
+        api_group: Some(PINNIPED_IDP_API_GROUP.into()),
+    })
+}
+
 #[macro_use]
 #[cfg(test)]
 mod tests {
@@ -196,9 +605,167 @@ mod tests {
         match tokio_test::block_on(call_pinniped_exchange("authorization", "https://example.com", "not a cert".as_bytes())) {
             Ok(_) => anyhow::bail!("expected error"),
             Err(e) => {
-                assert!(e.is::<openssl::error::ErrorStack>(), "got: {:#?}, want: openssl::error::ErrorStack", e);
+                assert!(e.is::<std::io::Error>(), "got: {:#?}, want: std::io::Error", e);
                 Ok(())
             },
         }
     }
+
+    // A credential that is easy to identify in assertions by the token it wraps.
+    fn bearer(token: &str) -> ExchangedCredential {
+        ExchangedCredential::BearerToken(token.to_string())
+    }
+
+    fn bearer_token(credential: &ExchangedCredential) -> &str {
+        match credential {
+            ExchangedCredential::BearerToken(t) => t,
+            ExchangedCredential::ClientCert(_) => panic!("expected a bearer credential"),
+        }
+    }
+
+    #[test]
+    #[serial(cachetest)]
+    fn test_cache_lookup_hit() -> Result<()> {
+        tokio_test::block_on(async {
+            IDENTITY_CACHE.write().await.clear();
+            let key = cache_key("tok", "https://example.com");
+            cache_store(key, "tok", "https://example.com", bearer("exchanged"), metav1::Time(Utc::now() + Duration::seconds(300))).await;
+
+            let hit = cache_lookup(key, "tok", "https://example.com", Duration::seconds(10)).await;
+            assert_eq!(bearer_token(&hit.expect("expected cache hit")), "exchanged");
+
+            // A different token that happens to share the bucket must never hit.
+            let collision = cache_lookup(key, "other-token", "https://example.com", Duration::seconds(10)).await;
+            assert!(collision.is_none(), "hashed-key collision leaked a credential");
+        });
+        Ok(())
+    }
+
+    #[test]
+    #[serial(cachetest)]
+    fn test_cache_lookup_within_skew_misses() -> Result<()> {
+        tokio_test::block_on(async {
+            IDENTITY_CACHE.write().await.clear();
+            let key = cache_key("tok", "https://example.com");
+            // Expires in 5s, which is inside the 10s skew, so it must not be reused.
+            cache_store(key, "tok", "https://example.com", bearer("exchanged"), metav1::Time(Utc::now() + Duration::seconds(5))).await;
+
+            let hit = cache_lookup(key, "tok", "https://example.com", Duration::seconds(10)).await;
+            assert!(hit.is_none(), "returned a credential within the expiry skew");
+        });
+        Ok(())
+    }
+
+    #[test]
+    #[serial(cachetest)]
+    fn test_cache_lookup_evicts_expired() -> Result<()> {
+        tokio_test::block_on(async {
+            IDENTITY_CACHE.write().await.clear();
+            let key = cache_key("tok", "https://example.com");
+            cache_store(key, "tok", "https://example.com", bearer("old"), metav1::Time(Utc::now() - Duration::seconds(1))).await;
+
+            // The stale entry is not handed out, and looking it up purges it in place.
+            let hit = cache_lookup(key, "tok", "https://example.com", Duration::seconds(10)).await;
+            assert!(hit.is_none(), "returned an expired credential");
+            assert!(!IDENTITY_CACHE.read().await.contains_key(&key), "expired entry not evicted on lookup");
+        });
+        Ok(())
+    }
+
+    #[test]
+    #[serial(cachetest)]
+    fn test_cache_store_evicts_expired() -> Result<()> {
+        tokio_test::block_on(async {
+            IDENTITY_CACHE.write().await.clear();
+            let expired_key = cache_key("old", "https://example.com");
+            cache_store(expired_key, "old", "https://example.com", bearer("old"), metav1::Time(Utc::now() - Duration::seconds(1))).await;
+
+            // Storing a fresh entry lazily evicts the already-expired one.
+            let fresh_key = cache_key("new", "https://example.com");
+            cache_store(fresh_key, "new", "https://example.com", bearer("new"), metav1::Time(Utc::now() + Duration::seconds(300))).await;
+
+            let cache = IDENTITY_CACHE.read().await;
+            assert!(!cache.contains_key(&expired_key), "expired entry was not evicted");
+            assert!(cache.contains_key(&fresh_key), "fresh entry was not stored");
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_identity_for_exchange_bearer_token() -> Result<()> {
+        // A token-based ClusterCredential (common with impersonation-proxy strategies)
+        // carries no cert/key, so the bearer branch must return before any PEM parsing.
+        let cred = ClusterCredential {
+            expiration_timestamp: metav1::Time(Utc::now() + Duration::seconds(300)),
+            token: Some("bearer-xyz".into()),
+            client_certificate_data: String::new(),
+            client_key_data: String::new(),
+        };
+        assert_eq!(bearer_token(&identity_for_exchange(&cred, b"ignored-ca")?), "bearer-xyz");
+        Ok(())
+    }
+
+    #[test]
+    #[serial(envtest)]
+    fn test_kubeconfig_for_identity_inline() -> Result<()> {
+        env::set_var(DEFAULT_PINNIPED_NAMESPACE, "pinniped-concierge");
+        let yaml = kubeconfig_for_identity("https://example.com", b"ca-data", Some("cert-pem"), Some("key-pem"))?;
+        assert!(yaml.contains("server: https://example.com"), "got: {}", yaml);
+        assert!(yaml.contains(&base64::encode("ca-data")), "got: {}", yaml);
+        assert!(yaml.contains(&base64::encode("cert-pem")), "got: {}", yaml);
+        assert!(yaml.contains("namespace: pinniped-concierge"), "got: {}", yaml);
+        Ok(())
+    }
+
+    #[test]
+    #[serial(envtest)]
+    fn test_kubeconfig_for_identity_exec() -> Result<()> {
+        env::set_var(DEFAULT_PINNIPED_NAMESPACE, "pinniped-concierge");
+        env::set_var(DEFAULT_PINNIPED_AUTHENTICATOR_NAME, "my-jwt-authenticator");
+        env::set_var(DEFAULT_PINNIPED_AUTHENTICATOR_TYPE, "JWTAuthenticator");
+        env::remove_var(DEFAULT_PINNIPED_IDP_NAME);
+        env::remove_var(DEFAULT_PINNIPED_IDP_TYPE);
+
+        // No inline cert/key, so the exec-credential plugin block should be emitted.
+        let yaml = kubeconfig_for_identity("https://example.com", b"ca-data", None, None)?;
+        assert!(yaml.contains("command: pinniped"), "got: {}", yaml);
+        assert!(yaml.contains("login"), "got: {}", yaml);
+        assert!(yaml.contains("static"), "got: {}", yaml);
+        // The concierge parameters must be rendered as real CLI flags, not internal env-var names.
+        assert!(yaml.contains("--concierge-endpoint=https://example.com"), "got: {}", yaml);
+        assert!(yaml.contains(&format!("--concierge-ca-bundle-data={}", base64::encode("ca-data"))), "got: {}", yaml);
+        assert!(yaml.contains("--concierge-authenticator-name=my-jwt-authenticator"), "got: {}", yaml);
+        assert!(yaml.contains("--concierge-authenticator-type=JWTAuthenticator"), "got: {}", yaml);
+        assert!(yaml.contains(&format!("--token-env={}", PINNIPED_TOKEN_ENV)), "got: {}", yaml);
+        assert!(!yaml.contains("DEFAULT_PINNIPED_AUTHENTICATOR_NAME"), "leaked internal env var name: {}", yaml);
+        // Without an IdP the static flow is used, not oidc, and no upstream flags leak in.
+        assert!(yaml.contains("static"), "got: {}", yaml);
+        assert!(!yaml.contains("--upstream-identity-provider"), "static block must not carry oidc-only flags: {}", yaml);
+        Ok(())
+    }
+
+    #[test]
+    #[serial(envtest)]
+    fn test_kubeconfig_for_identity_exec_oidc() -> Result<()> {
+        env::set_var(DEFAULT_PINNIPED_NAMESPACE, "pinniped-concierge");
+        env::set_var(DEFAULT_PINNIPED_AUTHENTICATOR_NAME, "my-jwt-authenticator");
+        env::set_var(DEFAULT_PINNIPED_AUTHENTICATOR_TYPE, "JWTAuthenticator");
+        env::set_var(DEFAULT_PINNIPED_IDP_NAME, "my-supervisor-idp");
+        env::set_var(DEFAULT_PINNIPED_IDP_TYPE, "oidc");
+
+        let yaml = kubeconfig_for_identity("https://example.com", b"ca-data", None, None)?;
+        // A configured IdP must select `login oidc`, which is the only subcommand
+        // that defines the upstream identity-provider flags.
+        assert!(yaml.contains("oidc"), "got: {}", yaml);
+        assert!(!yaml.contains("static"), "oidc flow must not emit the static subcommand: {}", yaml);
+        assert!(yaml.contains("--upstream-identity-provider-name=my-supervisor-idp"), "got: {}", yaml);
+        assert!(yaml.contains("--upstream-identity-provider-type=oidc"), "got: {}", yaml);
+        assert!(yaml.contains("--concierge-authenticator-name=my-jwt-authenticator"), "got: {}", yaml);
+        // The oidc flow is browser-interactive, so the static token-env flag is absent.
+        assert!(!yaml.contains("--token-env"), "oidc flow must not carry static --token-env: {}", yaml);
+
+        env::remove_var(DEFAULT_PINNIPED_IDP_NAME);
+        env::remove_var(DEFAULT_PINNIPED_IDP_TYPE);
+        Ok(())
+    }
 }